@@ -22,10 +22,15 @@
 //!
 //! Subsystems' APIs are defined separately from their implementation, leading to easier mocking.
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use futures::channel::{mpsc, oneshot};
+use futures::{FutureExt, SinkExt, StreamExt};
+use futures_timer::Delay;
 
 use sc_network::{ObservedRole, PeerId};
-use polkadot_primitives::{BlockNumber, Hash, Signature};
+use polkadot_primitives::{BlockNumber, Hash, Signature, SessionIndex};
 use polkadot_primitives::parachain::{
 	AbridgedCandidateReceipt, PoVBlock, ErasureChunk, BackedCandidate, Id as ParaId,
 	SignedAvailabilityBitfield, SigningContext, ValidatorId, ValidationCode, ValidatorIndex,
@@ -36,13 +41,26 @@ use polkadot_node_primitives::{
 
 pub type Bytes = Vec<u8>;
 
+/// A set of leaves that the overseer has observed to become activated or deactivated since the
+/// last such notification.
+///
+/// A leaf is activated when it is the head of a fork of the relay chain which has not yet been
+/// finalized away, and deactivated when it is no longer the head of such a fork, whether because
+/// it has been superseded by a descendant or because the chain it led has been abandoned.
+#[derive(Default, PartialEq, Clone, Debug)]
+pub struct ActiveLeavesUpdate {
+	/// New relay chain block hashes which are now considered leaves, with their block number.
+	pub activated: Vec<(Hash, BlockNumber)>,
+	/// Relay chain block hashes which were previously leaves but are no longer.
+	pub deactivated: Vec<Hash>,
+}
+
 /// Signals sent by an overseer to a subsystem.
 #[derive(PartialEq, Clone, Debug)]
 pub enum OverseerSignal {
-	/// `Subsystem` should start working on block-based work, given by the relay-chain block hash.
-	StartWork(Hash),
-	/// `Subsystem` should stop working on block-based work specified by the relay-chain block hash.
-	StopWork(Hash),
+	/// Subsystems should adjust their view of active leaves, given by the relay-chain block hashes
+	/// and numbers activated or deactivated since the last update.
+	ActiveLeavesUpdate(ActiveLeavesUpdate),
 	/// Conclude the work of the `Overseer` and all `Subsystem`s.
 	Conclude,
 }
@@ -126,6 +144,29 @@ pub enum NetworkBridgeMessage {
 	SendMessage(Vec<PeerId>, ProtocolId, Bytes),
 }
 
+impl std::fmt::Debug for NetworkBridgeMessage {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			NetworkBridgeMessage::RegisterEventProducer(protocol_id, _) => f
+				.debug_tuple("RegisterEventProducer")
+				.field(protocol_id)
+				.field(&"<event producer>")
+				.finish(),
+			NetworkBridgeMessage::ReportPeer(peer_id, cost_or_benefit) => f
+				.debug_tuple("ReportPeer")
+				.field(peer_id)
+				.field(cost_or_benefit)
+				.finish(),
+			NetworkBridgeMessage::SendMessage(peers, protocol_id, bytes) => f
+				.debug_tuple("SendMessage")
+				.field(peers)
+				.field(protocol_id)
+				.field(bytes)
+				.finish(),
+		}
+	}
+}
+
 /// Availability Distribution Message.
 #[derive(Debug)]
 pub enum AvailabilityDistributionMessage {
@@ -199,8 +240,6 @@ pub enum ProvisionableData {
 	BackedCandidate(BackedCandidate),
 	/// Misbehavior reports are self-contained proofs of validator misbehavior.
 	MisbehaviorReport(Hash, MisbehaviorReport),
-	/// Disputes trigger a broad dispute resolution process.
-	Dispute(Hash, Signature),
 }
 
 /// Message to the Provisioner.
@@ -213,15 +252,134 @@ pub enum ProvisionerMessage {
 	RequestBlockAuthorshipData(Hash, mpsc::Sender<ProvisionableData>),
 	/// This data should become part of a relay chain block
 	ProvisionableData(ProvisionableData),
+	/// Fetch the active disputes to include as extrinsics in the block under construction,
+	/// forwarded to the Dispute Coordinator's [`DisputeCoordinatorMessage::ActiveDisputes`]
+	/// rather than received as raw signatures via `ProvisionableData`.
+	///
+	/// [`DisputeCoordinatorMessage::ActiveDisputes`]: enum.DisputeCoordinatorMessage.html#variant.ActiveDisputes
+	RequestActiveDisputes(oneshot::Sender<Vec<(Hash, Hash)>>),
+}
+
+/// Messages received by the Dispute Coordinator subsystem.
+///
+/// This subsystem tracks disputes over candidate validity: it imports validators' statements as
+/// they arrive, keeps track of which candidates are currently disputed, and records enough of the
+/// opposing votes that the outcome can later be included as an extrinsic.
+#[derive(Debug)]
+pub enum DisputeCoordinatorMessage {
+	/// Import a validator's statement about a candidate's validity into an ongoing (or new)
+	/// dispute for that candidate.
+	ImportStatement {
+		/// Hash of the disputed candidate.
+		candidate_hash: Hash,
+		/// The session in which the candidate appears.
+		session: SessionIndex,
+		/// The validator's statement on the candidate's validity.
+		statement: SignedFullStatement,
+		/// Index of the validator who signed the statement.
+		validator_index: ValidatorIndex,
+		/// The validator's signature over the statement.
+		signature: Signature,
+	},
+	/// Fetch the relay-parent and candidate hash of every candidate that currently has an active
+	/// dispute.
+	ActiveDisputes(oneshot::Sender<Vec<(Hash, Hash)>>),
+	/// Fetch all statements known for a given candidate in a given session, to be assembled into
+	/// the extrinsic recording the dispute's outcome.
+	QueryCandidateVotes(Hash, SessionIndex, oneshot::Sender<Vec<(ValidatorIndex, SignedFullStatement)>>),
 }
 
 /// A message type tying together all message types that are used across Subsystems.
 #[derive(Debug)]
 pub enum AllMessages {
-	/// Message for the validation subsystem.
+	/// Message for the candidate validation subsystem.
 	CandidateValidation(CandidateValidationMessage),
 	/// Message for the candidate backing subsystem.
 	CandidateBacking(CandidateBackingMessage),
+	/// Message for the candidate selection subsystem.
+	CandidateSelection(CandidateSelectionMessage),
+	/// Message for the network bridge subsystem.
+	NetworkBridge(NetworkBridgeMessage),
+	/// Message for the availability distribution subsystem.
+	AvailabilityDistribution(AvailabilityDistributionMessage),
+	/// Message for the bitfield distribution subsystem.
+	BitfieldDistribution(BitfieldDistributionMessage),
+	/// Message for the availability store subsystem.
+	AvailabilityStore(AvailabilityStoreMessage),
+	/// Message for the runtime API subsystem.
+	RuntimeApi(RuntimeApiMessage),
+	/// Message for the statement distribution subsystem.
+	StatementDistribution(StatementDistributionMessage),
+	/// Message for the provisioner subsystem.
+	Provisioner(ProvisionerMessage),
+	/// Message for the dispute coordinator subsystem.
+	DisputeCoordinator(DisputeCoordinatorMessage),
+}
+
+impl From<CandidateValidationMessage> for AllMessages {
+	fn from(msg: CandidateValidationMessage) -> Self {
+		AllMessages::CandidateValidation(msg)
+	}
+}
+
+impl From<CandidateBackingMessage> for AllMessages {
+	fn from(msg: CandidateBackingMessage) -> Self {
+		AllMessages::CandidateBacking(msg)
+	}
+}
+
+impl From<CandidateSelectionMessage> for AllMessages {
+	fn from(msg: CandidateSelectionMessage) -> Self {
+		AllMessages::CandidateSelection(msg)
+	}
+}
+
+impl From<NetworkBridgeMessage> for AllMessages {
+	fn from(msg: NetworkBridgeMessage) -> Self {
+		AllMessages::NetworkBridge(msg)
+	}
+}
+
+impl From<AvailabilityDistributionMessage> for AllMessages {
+	fn from(msg: AvailabilityDistributionMessage) -> Self {
+		AllMessages::AvailabilityDistribution(msg)
+	}
+}
+
+impl From<BitfieldDistributionMessage> for AllMessages {
+	fn from(msg: BitfieldDistributionMessage) -> Self {
+		AllMessages::BitfieldDistribution(msg)
+	}
+}
+
+impl From<AvailabilityStoreMessage> for AllMessages {
+	fn from(msg: AvailabilityStoreMessage) -> Self {
+		AllMessages::AvailabilityStore(msg)
+	}
+}
+
+impl From<RuntimeApiMessage> for AllMessages {
+	fn from(msg: RuntimeApiMessage) -> Self {
+		AllMessages::RuntimeApi(msg)
+	}
+}
+
+impl From<StatementDistributionMessage> for AllMessages {
+	fn from(msg: StatementDistributionMessage) -> Self {
+		AllMessages::StatementDistribution(msg)
+	}
+}
+
+impl From<ProvisionerMessage> for AllMessages {
+	fn from(msg: ProvisionerMessage) -> Self {
+		AllMessages::Provisioner(msg)
+	}
+}
+
+impl From<DisputeCoordinatorMessage> for AllMessages {
+	fn from(msg: DisputeCoordinatorMessage) -> Self {
+		AllMessages::DisputeCoordinator(msg)
+	}
 }
 
 /// A message type that a subsystem receives from an overseer.
@@ -239,3 +397,277 @@ pub enum FromOverseer<M: std::fmt::Debug> {
 		msg: M,
 	},
 }
+
+/// The bounded capacity of a subsystem's message channel.
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// A callback invoked when a subsystem's bounded message channel is full and a send has to wait
+/// for capacity.
+pub trait ChannelFullCallback: Send + Sync {
+	/// Called when a message could not be queued immediately because the channel is at capacity.
+	fn on_channel_full(&self);
+}
+
+/// A callback that does nothing, used as the default when a subsystem is not otherwise interested
+/// in observing message-queue pressure.
+struct NoOpChannelFullCallback;
+
+impl ChannelFullCallback for NoOpChannelFullCallback {
+	fn on_channel_full(&self) {}
+}
+
+/// The sending half of a subsystem's bounded message channel.
+///
+/// Wraps a plain `mpsc::Sender` so that a send which has to wait for capacity first invokes the
+/// registered [`ChannelFullCallback`].
+///
+/// [`ChannelFullCallback`]: trait.ChannelFullCallback.html
+pub struct MeteredSender<M> {
+	inner: mpsc::Sender<M>,
+	on_full: Arc<dyn ChannelFullCallback>,
+}
+
+impl<M> Clone for MeteredSender<M> {
+	fn clone(&self) -> Self {
+		MeteredSender { inner: self.inner.clone(), on_full: self.on_full.clone() }
+	}
+}
+
+impl<M> MeteredSender<M> {
+	/// Send `msg`, invoking the registered [`ChannelFullCallback`] if the channel is currently at
+	/// capacity and the send has to wait for a slot.
+	///
+	/// [`ChannelFullCallback`]: trait.ChannelFullCallback.html
+	pub async fn send(&mut self, msg: M) -> Result<(), mpsc::SendError> {
+		match self.inner.try_send(msg) {
+			Ok(()) => Ok(()),
+			Err(err) if err.is_full() => {
+				self.on_full.on_channel_full();
+				self.inner.send(err.into_inner()).await
+			}
+			Err(err) => Err(err.into_send_error()),
+		}
+	}
+}
+
+/// The receiving half of a subsystem's split signal and message channels.
+///
+/// [`recv`] always prefers a pending signal over a pending message.
+///
+/// [`recv`]: #method.recv
+pub struct SubsystemIncomingMessages<M> {
+	signals: mpsc::UnboundedReceiver<OverseerSignal>,
+	messages: mpsc::Receiver<M>,
+}
+
+impl<M: std::fmt::Debug> SubsystemIncomingMessages<M> {
+	/// Create the bounded message channel and unbounded signal channel for a subsystem, returning
+	/// the sending halves and the receiving half to be polled via [`recv`](#method.recv).
+	/// Message-queue pressure is reported via the default, no-op [`ChannelFullCallback`]; use
+	/// [`with_channel_full_callback`] to observe it.
+	///
+	/// [`ChannelFullCallback`]: trait.ChannelFullCallback.html
+	/// [`with_channel_full_callback`]: #method.with_channel_full_callback
+	pub fn new() -> (MeteredSender<M>, mpsc::UnboundedSender<OverseerSignal>, Self) {
+		Self::with_channel_full_callback(Arc::new(NoOpChannelFullCallback))
+	}
+
+	/// Like [`new`](#method.new), but invoking `on_full` whenever a send on the returned
+	/// [`MeteredSender`] has to wait for capacity.
+	///
+	/// [`MeteredSender`]: struct.MeteredSender.html
+	pub fn with_channel_full_callback(
+		on_full: Arc<dyn ChannelFullCallback>,
+	) -> (MeteredSender<M>, mpsc::UnboundedSender<OverseerSignal>, Self) {
+		let (message_tx, message_rx) = mpsc::channel(CHANNEL_CAPACITY);
+		let (signal_tx, signal_rx) = mpsc::unbounded();
+
+		(
+			MeteredSender { inner: message_tx, on_full },
+			signal_tx,
+			SubsystemIncomingMessages { signals: signal_rx, messages: message_rx },
+		)
+	}
+
+	/// Receive the next signal or message, giving priority to any pending signal.
+	///
+	/// Returns `None` once the signal sender is dropped, even if the message channel is still
+	/// live — an overseer always tears both channels down together.
+	pub async fn recv(&mut self) -> Option<FromOverseer<M>> {
+		// Once the signal channel has yielded `None`, `select_biased!` would skip its branch and
+		// wait on the message channel alone, so report the closed signal channel on every call.
+		if futures::stream::FusedStream::is_terminated(&self.signals) {
+			return None;
+		}
+
+		// `select_biased!` already polls branches in declaration order, so the `signals` branch is
+		// always polled first; a separate `try_next` peek here is both redundant and unsound, as a
+		// closed-and-drained `UnboundedReceiver` observed via `try_next` can fail to be woken on a
+		// subsequent `.next()` poll inside the `select!`, hanging `recv()` forever.
+		futures::select_biased! {
+			signal = self.signals.next() => signal.map(FromOverseer::Signal),
+			msg = self.messages.next() => msg.map(|msg| FromOverseer::Communication { msg }),
+		}
+	}
+}
+
+/// An error in making a request to another subsystem and awaiting its reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+	/// The responding subsystem dropped the reply sender without sending a response, most likely
+	/// because it crashed or was otherwise unable to service the request.
+	Canceled,
+	/// The request was not answered within the given timeout.
+	TimedOut,
+}
+
+impl From<oneshot::Canceled> for RequestError {
+	fn from(_: oneshot::Canceled) -> Self {
+		RequestError::Canceled
+	}
+}
+
+/// Send a request to a subsystem and await its reply, failing with a [`RequestError`] instead of
+/// hanging forever if the subsystem drops the reply sender, does not answer within `timeout`, or
+/// cannot even accept the request within `timeout` because its message channel is full.
+///
+/// `to_subsystem` is the [`MeteredSender`] returned alongside a subsystem's
+/// [`SubsystemIncomingMessages`], e.g. from [`SubsystemIncomingMessages::new`]. `with_response`
+/// builds the request message from a fresh `oneshot::Sender`, e.g.
+/// `|tx| CandidateValidationMessage::Validate(relay_parent, receipt, pov, tx)`. Callers of
+/// request/response messages such as `CandidateValidationMessage::Validate`,
+/// `AvailabilityStoreMessage::QueryPoV`/`QueryChunk`, and `RuntimeApiRequest::*` should prefer this
+/// over constructing the `oneshot` pair by hand, so that a `RequestError::TimedOut` can be turned
+/// into a `NetworkBridgeMessage::ReportPeer` against the peer responsible for the stalled request.
+///
+/// [`RequestError`]: enum.RequestError.html
+/// [`MeteredSender`]: struct.MeteredSender.html
+/// [`SubsystemIncomingMessages`]: struct.SubsystemIncomingMessages.html
+/// [`SubsystemIncomingMessages::new`]: struct.SubsystemIncomingMessages.html#method.new
+pub async fn send_request<M, T>(
+	mut to_subsystem: MeteredSender<M>,
+	with_response: impl FnOnce(oneshot::Sender<T>) -> M,
+	timeout: Duration,
+) -> Result<T, RequestError> {
+	let (tx, rx) = oneshot::channel();
+	let mut deadline = Delay::new(timeout).fuse();
+
+	let sent: Result<(), RequestError> = futures::select! {
+		res = to_subsystem.send(with_response(tx)).fuse() => res.map_err(|_| RequestError::Canceled),
+		_ = deadline => Err(RequestError::TimedOut),
+	};
+	sent?;
+
+	futures::select! {
+		res = rx.fuse() => res.map_err(Into::into),
+		_ = deadline => Err(RequestError::TimedOut),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::executor::block_on;
+
+	enum TestMessage {
+		Ping(oneshot::Sender<u8>),
+	}
+
+	fn metered_channel<M>(capacity: usize) -> (MeteredSender<M>, mpsc::Receiver<M>) {
+		let (tx, rx) = mpsc::channel(capacity);
+		(MeteredSender { inner: tx, on_full: Arc::new(NoOpChannelFullCallback) }, rx)
+	}
+
+	#[test]
+	fn send_request_times_out_when_no_reply_is_sent() {
+		let (tx, mut rx) = metered_channel::<TestMessage>(1);
+
+		// Receive the request but never answer it, so the reply can never arrive; keeping this
+		// future pending for the rest of the test mirrors a subsystem that is stuck or overloaded.
+		// The received message (and its reply sender) must be kept alive here, not dropped, or
+		// dropping the reply sender would cancel the oneshot and resolve `send_request` via `Canceled`
+		// long before the timeout has a chance to fire.
+		let mut drain = Box::pin(async move {
+			let _msg = rx.next().await;
+			futures::future::pending::<()>().await
+		}).fuse();
+		let mut request = Box::pin(send_request(tx, TestMessage::Ping, Duration::from_millis(20))).fuse();
+
+		let result = block_on(async {
+			futures::select! {
+				result = request => result,
+				_ = drain => unreachable!("the drain future never completes"),
+			}
+		});
+
+		assert_eq!(result, Err(RequestError::TimedOut));
+	}
+
+	#[test]
+	fn send_request_returns_canceled_when_the_reply_sender_is_dropped() {
+		let (tx, mut rx) = metered_channel::<TestMessage>(1);
+
+		let mut drop_reply = Box::pin(async move {
+			match rx.next().await {
+				Some(TestMessage::Ping(reply_to)) => drop(reply_to),
+				None => unreachable!("request was never sent"),
+			}
+			futures::future::pending::<()>().await
+		}).fuse();
+		let mut request = Box::pin(send_request(tx, TestMessage::Ping, Duration::from_secs(5))).fuse();
+
+		let result = block_on(async {
+			futures::select! {
+				result = request => result,
+				_ = drop_reply => unreachable!("the drop_reply future never completes"),
+			}
+		});
+
+		assert_eq!(result, Err(RequestError::Canceled));
+	}
+
+	#[test]
+	fn send_request_times_out_when_the_channel_is_full() {
+		let (mut tx, rx) = metered_channel::<TestMessage>(0);
+
+		// Fill the channel's one guaranteed slot and never drain it, so the initial `send` inside
+		// `send_request` itself has to wait for capacity that never frees up.
+		let (fill_tx, _fill_rx) = oneshot::channel();
+		block_on(tx.send(TestMessage::Ping(fill_tx))).unwrap();
+
+		let result = block_on(send_request(tx, TestMessage::Ping, Duration::from_millis(20)));
+		assert_eq!(result, Err(RequestError::TimedOut));
+
+		drop(rx);
+	}
+
+	#[test]
+	fn recv_prefers_a_pending_signal_over_a_pending_message() {
+		let (mut message_tx, signal_tx, mut incoming) = SubsystemIncomingMessages::<u8>::new();
+
+		block_on(message_tx.send(1u8)).unwrap();
+		signal_tx.unbounded_send(OverseerSignal::Conclude).unwrap();
+
+		match block_on(incoming.recv()) {
+			Some(FromOverseer::Signal(OverseerSignal::Conclude)) => {}
+			other => panic!("expected the pending signal to be delivered first, got a {:?} instead", other),
+		}
+	}
+
+	#[test]
+	fn recv_resolves_when_only_the_signal_sender_is_dropped() {
+		let (message_tx, signal_tx, mut incoming) = SubsystemIncomingMessages::<u8>::new();
+
+		// Drop only the signal sender; the message sender is kept alive (and simply never sent on)
+		// to mirror the two channels tearing down independently. `recv()` must still resolve rather
+		// than hang waiting on the message channel.
+		drop(signal_tx);
+
+		match block_on(incoming.recv()) {
+			None => {}
+			other => panic!("expected recv() to resolve to None, got a {:?} instead", other),
+		}
+
+		drop(message_tx);
+	}
+}